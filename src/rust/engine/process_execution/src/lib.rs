@@ -0,0 +1,93 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+pub mod nailgun;
+
+/// A process to be executed, either directly or (for JVM-ish tools) via a reusable nailgun
+/// server keyed by its `fingerprint`.
+#[derive(Clone)]
+pub struct Process {
+  pub argv: Vec<String>,
+  /// Workdir-relative paths that must be present before the process runs, mapped to the
+  /// absolute path to copy their current content from.
+  pub input_files: BTreeMap<PathBuf, PathBuf>,
+  /// When run via `NailgunPool`, whether to capture the workdir's contents into the `Store`
+  /// as a content-addressed directory digest on release.
+  pub capture_outputs: bool,
+  /// Overrides the `argv[0]` presented to the kernel (and so visible to e.g. `ps`) without
+  /// changing which binary `argv[0]` itself selects for exec.
+  pub argv0: Option<String>,
+  /// Actions run in the forked child, before exec, via `CommandExt::pre_exec`. Used to drop
+  /// privileges or otherwise adjust the child's environment in ways std's `Command` has no
+  /// direct API for.
+  ///
+  /// `fingerprint` distinguishes these by `Arc` pointer, not content, so a caller that wants a
+  /// nailgun server spawned with a particular `pre_exec` action reused across calls must keep
+  /// that same `Arc` around (e.g. build it once and clone it into each `Process`) rather than
+  /// constructing an equivalent-but-distinct closure per call, or reuse will never hit.
+  pub pre_exec: Vec<Arc<dyn Fn() -> io::Result<()> + Send + Sync>>,
+  /// The uid/gid to run the spawned process as, if overriding the parent's.
+  pub uid: Option<u32>,
+  pub gid: Option<u32>,
+}
+
+impl Process {
+  pub fn new(argv: Vec<String>) -> Process {
+    Process {
+      argv,
+      input_files: BTreeMap::new(),
+      capture_outputs: false,
+      argv0: None,
+      pre_exec: Vec::new(),
+      uid: None,
+      gid: None,
+    }
+  }
+
+  /// Identifies the command line and spawn identity (`argv0`/`uid`/`gid`/`pre_exec`) this
+  /// `Process` would run with, independent of its current inputs. Used to key reusable nailgun
+  /// servers: two `Process`es with the same fingerprint may be served by the same running
+  /// server even if their `input_files` differ.
+  ///
+  /// `uid`/`gid`/`argv0` are included so that, say, a caller asking to run under a dedicated
+  /// unprivileged uid is never handed back a server that an earlier, differently-configured
+  /// caller with the same `argv` already spawned under a different identity. `pre_exec` actions
+  /// aren't hashable by content, so each is identified by its `Arc` pointer instead: stable
+  /// across `Process` clones of the same call site, and distinct across call sites that install
+  /// different actions. That distinctness would otherwise be spoofable once the original `Arc`
+  /// is dropped and its allocation reused for an unrelated closure at the same address; nailgun's
+  /// `NailgunPool` closes that gap by keeping a cached server's own `pre_exec` `Arc`s alive for
+  /// as long as the server itself is cached, so no address it fingerprinted against can be
+  /// reassigned out from under it.
+  pub fn fingerprint(&self) -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    self.argv.hash(&mut hasher);
+    self.argv0.hash(&mut hasher);
+    self.uid.hash(&mut hasher);
+    self.gid.hash(&mut hasher);
+    for action in &self.pre_exec {
+      (Arc::as_ptr(action) as *const () as usize).hash(&mut hasher);
+    }
+    hasher.finish()
+  }
+}
+
+impl fmt::Debug for Process {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Process")
+      .field("argv", &self.argv)
+      .field("input_files", &self.input_files)
+      .field("capture_outputs", &self.capture_outputs)
+      .field("argv0", &self.argv0)
+      .field("pre_exec", &format_args!("[{} actions]", self.pre_exec.len()))
+      .field("uid", &self.uid)
+      .field("gid", &self.gid)
+      .finish()
+  }
+}
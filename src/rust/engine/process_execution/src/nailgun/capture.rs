@@ -0,0 +1,290 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use hashing::Digest;
+use store::Store;
+use task_executor::Executor;
+
+/// A file entry in a captured `Directory`: its name, content digest, and executable bit.
+#[derive(Clone, Debug)]
+pub struct FileNode {
+  pub name: String,
+  pub digest: Digest,
+  pub is_executable: bool,
+}
+
+/// A symlink entry in a captured `Directory`: its name and link target.
+#[derive(Clone, Debug)]
+pub struct SymlinkNode {
+  pub name: String,
+  pub target: String,
+}
+
+/// A content-addressed directory tree entry: files, subdirectories (named and keyed by the
+/// digest of their own `Directory`), and symlinks.
+#[derive(Clone, Debug, Default)]
+pub struct Directory {
+  pub files: Vec<FileNode>,
+  pub directories: Vec<(String, Digest)>,
+  pub symlinks: Vec<SymlinkNode>,
+}
+
+enum EntryKind {
+  File { is_executable: bool },
+  Dir,
+  Symlink { target: String },
+}
+
+struct WalkedEntry {
+  name: String,
+  kind: EntryKind,
+}
+
+/// Recursively captures `root` into `store` as a tree of content-addressed `Directory`s, built
+/// bottom-up (each subdirectory is stored before the `Directory` entry that references it by
+/// digest), skipping any entries named in `skip_names` (nailgun's own bookkeeping files).
+///
+/// Mirrors a castore-style filesystem import: file contents become blobs, executable
+/// permission bits are preserved on the resulting `FileNode`s, and symlinks are represented by
+/// their target rather than followed. The directory walk and file reads are run via `executor`
+/// (`spawn_blocking`) rather than inline, since a real build output tree can be large enough
+/// that walking it synchronously would stall every other task sharing this runtime.
+pub async fn capture_directory(
+  executor: &Executor,
+  store: &Store,
+  root: PathBuf,
+  skip_names: &[&str],
+) -> Result<Digest, String> {
+  let skip_names: Vec<String> = skip_names.iter().map(|s| s.to_string()).collect();
+  capture_directory_inner(executor, store, root, skip_names).await
+}
+
+async fn capture_directory_inner(
+  executor: &Executor,
+  store: &Store,
+  root: PathBuf,
+  skip_names: Vec<String>,
+) -> Result<Digest, String> {
+  let blocking_root = root.clone();
+  let blocking_skip_names = skip_names.clone();
+  let entries = executor
+    .spawn_blocking(move || list_dir(&blocking_root, &blocking_skip_names))
+    .await
+    .map_err(|e| format!("Nailgun output capture task for {} panicked: {}", root.display(), e))??;
+
+  let mut directory = Directory::default();
+  for entry in entries {
+    match entry.kind {
+      EntryKind::Symlink { target } => directory.symlinks.push(SymlinkNode { name: entry.name, target }),
+      EntryKind::Dir => {
+        let child_root = root.join(&entry.name);
+        // Bottom-up: the subdirectory's own Directory (and everything under it) is stored
+        // before we record its digest in the parent we're building here.
+        let digest = Box::pin(capture_directory_inner(
+          executor,
+          store,
+          child_root,
+          skip_names.clone(),
+        ))
+        .await?;
+        directory.directories.push((entry.name, digest));
+      }
+      EntryKind::File { is_executable } => {
+        let path = root.join(&entry.name);
+        let blocking_path = path.clone();
+        let content = executor
+          .spawn_blocking(move || fs::read(&blocking_path))
+          .await
+          .map_err(|e| format!("Nailgun output capture task for {} panicked: {}", path.display(), e))?
+          .map_err(|e| format!("Failed to read nailgun output {}: {}", path.display(), e))?;
+        let digest = store
+          .store_file_bytes(Bytes::from(content), true)
+          .await
+          .map_err(|e| format!("Failed to store nailgun output {}: {}", path.display(), e))?;
+        directory.files.push(FileNode {
+          name: entry.name,
+          digest,
+          is_executable,
+        });
+      }
+    }
+  }
+
+  record_directory(store, &directory)
+    .await
+    .map_err(|e| format!("Failed to record nailgun output directory {}: {}", root.display(), e))
+}
+
+/// Serializes `directory` and stores it as a blob, the same way a `FileNode`'s content is
+/// stored; the resulting digest is what a parent's `directories` entry (or the root of the
+/// tree) refers to.
+async fn record_directory(store: &Store, directory: &Directory) -> Result<Digest, String> {
+  store.store_file_bytes(encode_directory(directory), true).await
+}
+
+/// Loads and decodes a `Directory` previously written by `record_directory`. Exposed so tests
+/// (and anything that later wants to materialize a captured tree back onto disk) can inspect
+/// exactly what got captured, rather than only observing the opaque root digest.
+pub async fn load_directory(store: &Store, digest: Digest) -> Result<Directory, String> {
+  let bytes = store
+    .load_file_bytes(digest)
+    .await
+    .map_err(|e| format!("Failed to load nailgun output directory: {}", e))?
+    .ok_or_else(|| "Nailgun output directory digest not found in store".to_owned())?;
+  decode_directory(&bytes)
+}
+
+/// A one-entry-per-line, tab-delimited encoding of a `Directory`:
+///   F\t<name>\t<fingerprint>:<size_bytes>\t<is_executable:0|1>
+///   D\t<name>\t<fingerprint>:<size_bytes>
+///   L\t<name>\t<target>
+///
+/// `name`/`target` are escaped (see `escape_field`) since either may legally contain a tab or
+/// newline, which would otherwise be indistinguishable from the field and entry delimiters.
+/// Spaces are not delimiters and are written as-is.
+fn encode_directory(directory: &Directory) -> Bytes {
+  let mut contents = String::new();
+  for file in &directory.files {
+    contents.push_str(&format!(
+      "F\t{}\t{}:{}\t{}\n",
+      escape_field(&file.name),
+      file.digest.hash.to_hex(),
+      file.digest.size_bytes,
+      if file.is_executable { 1 } else { 0 },
+    ));
+  }
+  for (name, digest) in &directory.directories {
+    contents.push_str(&format!(
+      "D\t{}\t{}:{}\n",
+      escape_field(name),
+      digest.hash.to_hex(),
+      digest.size_bytes
+    ));
+  }
+  for symlink in &directory.symlinks {
+    contents.push_str(&format!(
+      "L\t{}\t{}\n",
+      escape_field(&symlink.name),
+      escape_field(&symlink.target)
+    ));
+  }
+  Bytes::from(contents)
+}
+
+fn decode_directory(bytes: &[u8]) -> Result<Directory, String> {
+  let text = std::str::from_utf8(bytes).map_err(|e| format!("Corrupt nailgun output directory: {}", e))?;
+  let mut directory = Directory::default();
+  for line in text.lines() {
+    let mut fields = line.split('\t');
+    let kind = fields.next().ok_or("Corrupt nailgun output directory entry")?;
+    let name = unescape_field(fields.next().ok_or("Corrupt nailgun output directory entry")?)?;
+    match kind {
+      "F" => {
+        let digest = parse_digest(fields.next().ok_or("Corrupt nailgun output directory entry")?)?;
+        let is_executable = fields.next().ok_or("Corrupt nailgun output directory entry")? == "1";
+        directory.files.push(FileNode {
+          name,
+          digest,
+          is_executable,
+        });
+      }
+      "D" => {
+        let digest = parse_digest(fields.next().ok_or("Corrupt nailgun output directory entry")?)?;
+        directory.directories.push((name, digest));
+      }
+      "L" => {
+        let target = unescape_field(fields.next().ok_or("Corrupt nailgun output directory entry")?)?;
+        directory.symlinks.push(SymlinkNode { name, target });
+      }
+      other => return Err(format!("Unknown nailgun output directory entry kind: {}", other)),
+    }
+  }
+  Ok(directory)
+}
+
+/// Escapes backslashes, tabs, and newlines in a single field so it round-trips unambiguously
+/// through the tab/newline-delimited format `encode_directory` writes.
+fn escape_field(value: &str) -> String {
+  let mut escaped = String::with_capacity(value.len());
+  for c in value.chars() {
+    match c {
+      '\\' => escaped.push_str("\\\\"),
+      '\t' => escaped.push_str("\\t"),
+      '\n' => escaped.push_str("\\n"),
+      other => escaped.push(other),
+    }
+  }
+  escaped
+}
+
+fn unescape_field(value: &str) -> Result<String, String> {
+  let mut unescaped = String::with_capacity(value.len());
+  let mut chars = value.chars();
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      unescaped.push(c);
+      continue;
+    }
+    match chars.next() {
+      Some('\\') => unescaped.push('\\'),
+      Some('t') => unescaped.push('\t'),
+      Some('n') => unescaped.push('\n'),
+      _ => return Err(format!("Corrupt nailgun output directory entry escape in {:?}", value)),
+    }
+  }
+  Ok(unescaped)
+}
+
+fn parse_digest(field: &str) -> Result<Digest, String> {
+  let (hash, size_bytes) = field
+    .split_once(':')
+    .ok_or_else(|| format!("Corrupt nailgun output directory digest: {}", field))?;
+  let hash =
+    hashing::Fingerprint::from_hex_string(hash).map_err(|e| format!("Corrupt nailgun output directory digest: {}", e))?;
+  let size_bytes: usize = size_bytes
+    .parse()
+    .map_err(|e| format!("Corrupt nailgun output directory digest: {}", e))?;
+  Ok(Digest::new(hash, size_bytes))
+}
+
+/// Lists `root`'s immediate children (excluding `skip_names`), classifying each as a file,
+/// directory, or symlink. Run via `executor.spawn_blocking`, never inline on the async runtime.
+fn list_dir(root: &Path, skip_names: &[String]) -> Result<Vec<WalkedEntry>, String> {
+  let mut dir_entries: Vec<fs::DirEntry> = fs::read_dir(root)
+    .map_err(|e| format!("Failed to read nailgun workdir {}: {}", root.display(), e))?
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| format!("Failed to read nailgun workdir {}: {}", root.display(), e))?;
+  dir_entries.sort_by_key(|entry| entry.file_name());
+
+  let mut entries = Vec::new();
+  for entry in dir_entries {
+    let name = entry.file_name().to_string_lossy().into_owned();
+    if skip_names.iter().any(|skip| skip == &name) {
+      continue;
+    }
+    let path = entry.path();
+    let file_type = entry
+      .file_type()
+      .map_err(|e| format!("Failed to inspect {}: {}", path.display(), e))?;
+
+    let kind = if file_type.is_symlink() {
+      let target = fs::read_link(&path).map_err(|e| format!("Failed to read symlink {}: {}", path.display(), e))?;
+      EntryKind::Symlink {
+        target: target.to_string_lossy().into_owned(),
+      }
+    } else if file_type.is_dir() {
+      EntryKind::Dir
+    } else {
+      let is_executable = entry
+        .metadata()
+        .map(|metadata| metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false);
+      EntryKind::File { is_executable }
+    };
+
+    entries.push(WalkedEntry { name, kind });
+  }
+  Ok(entries)
+}
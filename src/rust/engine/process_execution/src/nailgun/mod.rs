@@ -0,0 +1,516 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hashing::Digest;
+use parking_lot::Mutex;
+use store::Store;
+use task_executor::Executor;
+use tokio::io::AsyncBufReadExt;
+use tokio::process::{Child, Command};
+
+use crate::Process;
+
+mod capture;
+pub use capture::{capture_directory, load_directory, Directory, FileNode, SymlinkNode};
+
+#[cfg(target_os = "linux")]
+mod pidfd;
+
+#[cfg(test)]
+mod tests;
+
+/// A path relative to a nailgun server's workdir.
+pub type RelativePath = PathBuf;
+
+/// The mtime we last observed for each materialized input of a server's workdir. Persisted
+/// alongside the workdir so that a reused server only needs to copy in the inputs that
+/// actually changed since the last `acquire`, rather than the whole input set.
+type FingerprintMap = BTreeMap<RelativePath, SystemTime>;
+
+const FINGERPRINT_MAP_FILENAME: &str = ".pants.nailgun-fingerprint-map";
+const CHANGED_PATHS_FILENAME: &str = ".pants.nailgun-changed-paths";
+
+struct RunningProcess {
+  fingerprint: u64,
+  workdir: PathBuf,
+  port: u16,
+  child: Child,
+  in_use: bool,
+  /// The declared inputs (classpath jars, etc) this server was started with, and the max mtime
+  /// observed across them at startup. Used to detect a server that has gone stale because its
+  /// inputs were rebuilt on disk underneath it, even though its fingerprint hasn't changed.
+  anchor: SystemTime,
+  anchor_inputs: Vec<PathBuf>,
+  /// Kept alive only so that `fingerprint`'s hash of each action's `Arc` pointer can't be
+  /// spoofed: as long as this server is cached here, the allocation backing each of these
+  /// pointers stays live, so no later, differently-behaved `pre_exec` action can be allocated
+  /// at the same address and collide with it. Never read otherwise.
+  _pre_exec: Vec<Arc<dyn Fn() -> io::Result<()> + Send + Sync>>,
+  /// A pidfd for the server, when available: lets us poll for liveness (reaping still goes
+  /// through `child`) without the TOCTOU race inherent in looking the pid back up by number, which could have
+  /// been recycled by an unrelated process by the time we check it. Opened immediately after
+  /// `spawn()` returns rather than atomically at fork time; see `pidfd::PidFd`'s doc comment
+  /// for why that's still race-free in practice.
+  #[cfg(target_os = "linux")]
+  pidfd: Option<pidfd::PidFd>,
+}
+
+impl RunningProcess {
+  /// Whether the server is still alive. Prefers the pidfd (immune to pid reuse) where one was
+  /// obtained at spawn time, falling back to a non-blocking wait on the child otherwise.
+  fn is_alive(&mut self) -> bool {
+    #[cfg(target_os = "linux")]
+    if let Some(pidfd) = &self.pidfd {
+      return pidfd.is_alive().unwrap_or(false);
+    }
+    matches!(self.child.try_wait(), Ok(None))
+  }
+}
+
+/// A pool of long-lived "nailgun" servers, keyed by `Process` fingerprint, so that repeated
+/// calls with the same command line can reuse a warm server (and its workdir) instead of
+/// forking and re-materializing inputs from scratch every time.
+pub struct NailgunPool {
+  workdir_base: PathBuf,
+  size: usize,
+  store: Store,
+  executor: Executor,
+  processes: Arc<Mutex<Vec<RunningProcess>>>,
+}
+
+impl NailgunPool {
+  pub fn new(workdir_base: PathBuf, size: usize, store: Store, executor: Executor) -> NailgunPool {
+    NailgunPool {
+      workdir_base,
+      size,
+      store,
+      executor,
+      processes: Arc::new(Mutex::new(Vec::new())),
+    }
+  }
+
+  /// Acquires a server for the given `Process`, reusing one whose fingerprint already matches
+  /// (incrementally syncing its workdir to the process' current inputs), or spawning a new one
+  /// otherwise. When the pool is already at capacity, a newly spawned server evicts an idle
+  /// entry (if there is one) before it joins the pool.
+  ///
+  /// A fingerprint match whose declared inputs have changed on disk since the server started
+  /// (e.g. a classpath jar was rebuilt underneath it) is torn down and respawned rather than
+  /// reused, even though its fingerprint is unchanged.
+  pub async fn acquire(&self, process: Process) -> Result<NailgunProcessHandle, String> {
+    let fingerprint = process.fingerprint();
+
+    enum Match {
+      Fresh { workdir: PathBuf, port: u16 },
+      Stale { workdir: PathBuf, evicted: Child },
+    }
+
+    let found = {
+      let mut processes = self.processes.lock();
+      match processes
+        .iter()
+        .position(|p| p.fingerprint == fingerprint && !p.in_use)
+      {
+        None => None,
+        Some(index) => {
+          let stale = find_stale_file(processes[index].anchor, &processes[index].anchor_inputs).is_some();
+          let dead = !processes[index].is_alive();
+          if stale || dead {
+            let mut evicted = processes.remove(index);
+            // Closing a race where `acquire` hands back a dead entry: if the server already
+            // exited there's nothing to kill, so only ask for that when we're the ones
+            // declaring it stale.
+            if !dead {
+              let _ = evicted.child.start_kill();
+            }
+            Some(Match::Stale {
+              workdir: evicted.workdir,
+              evicted: evicted.child,
+            })
+          } else {
+            processes[index].in_use = true;
+            Some(Match::Fresh {
+              workdir: processes[index].workdir.clone(),
+              port: processes[index].port,
+            })
+          }
+        }
+      }
+    };
+
+    match found {
+      Some(Match::Fresh { workdir, port }) => {
+        if let Err(e) = self.sync_workdir(&workdir, &process) {
+          // The entry is still alive and otherwise reusable; only the sync failed (a transient
+          // stat error, a full disk, ...). Hand it back to the pool rather than leaving it
+          // wedged at `in_use = true` forever, which would otherwise permanently strand this
+          // slot on every sync failure.
+          let mut processes = self.processes.lock();
+          if let Some(running) = processes.iter_mut().find(|p| p.workdir == workdir) {
+            running.in_use = false;
+          }
+          return Err(e);
+        }
+        Ok(NailgunProcessHandle {
+          pool: self.processes.clone(),
+          store: self.store.clone(),
+          executor: self.executor.clone(),
+          workdir,
+          port,
+          capture_outputs: process.capture_outputs,
+        })
+      }
+      Some(Match::Stale { workdir, mut evicted }) => {
+        // Wait for the evicted server to actually exit before reusing its workdir/port: `spawn`
+        // below binds the same deterministic `workdir_base/nailgun-<fingerprint>` path, and if
+        // the old process hasn't released its listening socket yet, the new bind would race it.
+        let _ = evicted.wait().await;
+        // The workdir may contain outputs built against the stale inputs; start the
+        // replacement server from a clean slate rather than incrementally syncing it.
+        let _ = fs::remove_dir_all(&workdir);
+        self.spawn(process).await
+      }
+      None => self.spawn(process).await,
+    }
+  }
+
+  async fn spawn(&self, process: Process) -> Result<NailgunProcessHandle, String> {
+    let fingerprint = process.fingerprint();
+    let workdir = self.workdir_base.join(format!("nailgun-{:x}", fingerprint));
+    fs::create_dir_all(&workdir)
+      .map_err(|e| format!("Failed to create nailgun workdir {}: {}", workdir.display(), e))?;
+
+    // A freshly created workdir has no fingerprint map yet, so this sync materializes the
+    // whole input set.
+    self.sync_workdir(&workdir, &process)?;
+
+    let mut command = Command::new(&process.argv[0]);
+    command
+      .args(&process.argv[1..])
+      .current_dir(&workdir)
+      .stdout(Stdio::piped())
+      .stderr(Stdio::inherit());
+    if let Some(argv0) = &process.argv0 {
+      command.arg0(argv0);
+    }
+    if let Some(uid) = process.uid {
+      command.uid(uid);
+    }
+    if let Some(gid) = process.gid {
+      command.gid(gid);
+    }
+    for action in process.pre_exec.clone() {
+      // Safety: the closures a `Process` carries are documented (see `Process::pre_exec`) as
+      // being run in the forked child before exec, which is exactly what `pre_exec` does; the
+      // caller is responsible for only doing async-signal-safe work in them.
+      unsafe {
+        command.pre_exec(move || action());
+      }
+    }
+    let mut child = command
+      .spawn()
+      .map_err(|e| format!("Failed to spawn nailgun server: {}", e))?;
+
+    // Opened as soon as possible after spawn so that every later liveness check goes through
+    // the pidfd rather than a pid number; see `pidfd::PidFd`'s doc comment for why the gap
+    // between spawn and this call doesn't reintroduce the race it's meant to close.
+    #[cfg(target_os = "linux")]
+    let server_pidfd = pidfd::open(&child).ok();
+
+    let port = Self::read_port(&mut child).await?;
+
+    let anchor_inputs: Vec<PathBuf> = process.input_files.values().cloned().collect();
+    let anchor = anchor_inputs
+      .iter()
+      .filter_map(|input| fs::metadata(input).ok().and_then(|m| m.modified().ok()))
+      .max()
+      .unwrap_or(UNIX_EPOCH);
+
+    let evicted = {
+      let mut processes = self.processes.lock();
+      if processes.len() >= self.size {
+        processes.iter().position(|p| !p.in_use).map(|index| {
+          let mut evicted = processes.remove(index);
+          let _ = evicted.child.start_kill();
+          evicted.child
+        })
+      } else {
+        None
+      }
+    };
+    // Wait for the evicted server to actually exit (rather than just asking it to) before this
+    // new entry takes its place in the pool: a later `acquire` for the evicted fingerprint will
+    // reuse the exact same deterministic workdir/port, which would race a not-yet-dead process.
+    if let Some(mut evicted) = evicted {
+      let _ = evicted.wait().await;
+    }
+
+    {
+      let mut processes = self.processes.lock();
+      processes.push(RunningProcess {
+        fingerprint,
+        workdir: workdir.clone(),
+        port,
+        child,
+        in_use: true,
+        anchor,
+        anchor_inputs,
+        _pre_exec: process.pre_exec.clone(),
+        #[cfg(target_os = "linux")]
+        pidfd: server_pidfd,
+      });
+    }
+
+    Ok(NailgunProcessHandle {
+      pool: self.processes.clone(),
+      store: self.store.clone(),
+      executor: self.executor.clone(),
+      workdir,
+      port,
+      capture_outputs: process.capture_outputs,
+    })
+  }
+
+  /// Nailgun servers report the port they're listening on via stdout; read lines until one
+  /// contains a trailing port number.
+  async fn read_port(child: &mut Child) -> Result<u16, String> {
+    let stdout = child
+      .stdout
+      .take()
+      .ok_or_else(|| "Nailgun server had no stdout".to_owned())?;
+    let mut lines = tokio::io::BufReader::new(stdout).lines();
+    while let Some(line) = lines
+      .next_line()
+      .await
+      .map_err(|e| format!("Failed to read nailgun server output: {}", e))?
+    {
+      if let Some(port) = parse_port(&line) {
+        return Ok(port);
+      }
+    }
+    Err("Nailgun server exited without reporting a port".to_owned())
+  }
+
+  /// Incrementally syncs `workdir` to `process.input_files`: copies files that are new or
+  /// whose mtime differs from the one recorded at the last sync, and removes files that
+  /// disappeared.
+  fn sync_workdir(&self, workdir: &Path, process: &Process) -> Result<(), String> {
+    let old_map = load_fingerprint_map(workdir);
+
+    let mut new_map = FingerprintMap::new();
+    let mut changed = Vec::new();
+    for (rel_path, src_path) in &process.input_files {
+      let mtime = fs::metadata(src_path)
+        .and_then(|m| m.modified())
+        .map_err(|e| format!("Failed to stat nailgun input {}: {}", src_path.display(), e))?;
+      if old_map.get(rel_path) != Some(&mtime) {
+        changed.push(rel_path.clone());
+      }
+      new_map.insert(rel_path.clone(), mtime);
+    }
+
+    let deleted: Vec<&RelativePath> = old_map
+      .keys()
+      .filter(|rel_path| !new_map.contains_key(*rel_path))
+      .collect();
+
+    // Deletions land before copies: a path that changed from a directory to a file (or vice
+    // versa) needs the stale entry gone before we materialize the new one in its place.
+    for rel_path in deleted {
+      let dest = workdir.join(rel_path);
+      let removed = if dest.is_dir() {
+        fs::remove_dir_all(&dest)
+      } else {
+        fs::remove_file(&dest)
+      };
+      match removed {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+          return Err(format!("Failed to remove nailgun input {}: {}", dest.display(), e));
+        }
+        _ => {}
+      }
+    }
+
+    if !changed.is_empty() {
+      // Rather than pass every changed path as a command-line argument (which can blow past
+      // OS argument-list limits when a lot of inputs change at once), write them to a single
+      // file for the copy step below to consume.
+      let list_path = workdir.join(CHANGED_PATHS_FILENAME);
+      let list_contents = changed
+        .iter()
+        .map(|rel_path| rel_path.display().to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+      fs::write(&list_path, list_contents)
+        .map_err(|e| format!("Failed to write nailgun changed-paths list: {}", e))?;
+      let result = copy_changed_paths(&list_path, workdir, process);
+      let _ = fs::remove_file(&list_path);
+      result?;
+    }
+
+    // Only persist the new map once the copies and deletions it describes have actually
+    // landed: if we crash first, the stale map left on disk will make everything look dirty
+    // again next time, forcing a conservative re-copy rather than falsely reusing files that
+    // never arrived.
+    store_fingerprint_map(workdir, &new_map)
+      .map_err(|e| format!("Failed to persist nailgun fingerprint map: {}", e))?;
+
+    Ok(())
+  }
+}
+
+/// Reads the changed-paths list written by `sync_workdir` and copies each entry in from the
+/// corresponding `process.input_files` source.
+fn copy_changed_paths(list_path: &Path, workdir: &Path, process: &Process) -> Result<(), String> {
+  let contents = fs::read_to_string(list_path)
+    .map_err(|e| format!("Failed to read nailgun changed-paths list: {}", e))?;
+  for line in contents.lines() {
+    let rel_path = RelativePath::from(line);
+    let src_path = process.input_files.get(&rel_path).ok_or_else(|| {
+      format!(
+        "Nailgun input {} listed as changed but missing from process inputs",
+        rel_path.display()
+      )
+    })?;
+    let dest_path = workdir.join(&rel_path);
+    if let Some(parent) = dest_path.parent() {
+      // An ancestor of this path may itself have been a file under the old input set (e.g.
+      // "foo" flipping to "foo/a.txt"); clear it so `create_dir_all` doesn't choke on it.
+      if parent.is_file() {
+        fs::remove_file(parent)
+          .map_err(|e| format!("Failed to remove stale nailgun file {}: {}", parent.display(), e))?;
+      }
+      fs::create_dir_all(parent).map_err(|e| {
+        format!(
+          "Failed to create nailgun workdir directory {}: {}",
+          parent.display(),
+          e
+        )
+      })?;
+    }
+    // The reverse flip ("foo/a.txt" collapsing to a plain file "foo") leaves a stale,
+    // now-empty directory at `dest_path` that `fs::copy` can't write through.
+    if dest_path.is_dir() {
+      fs::remove_dir_all(&dest_path)
+        .map_err(|e| format!("Failed to remove stale nailgun directory {}: {}", dest_path.display(), e))?;
+    }
+    fs::copy(src_path, &dest_path)
+      .map_err(|e| format!("Failed to copy nailgun input {}: {}", src_path.display(), e))?;
+  }
+  Ok(())
+}
+
+fn load_fingerprint_map(workdir: &Path) -> FingerprintMap {
+  let path = workdir.join(FINGERPRINT_MAP_FILENAME);
+  let contents = match fs::read_to_string(&path) {
+    Ok(contents) => contents,
+    Err(_) => return FingerprintMap::new(),
+  };
+  contents
+    .lines()
+    .filter_map(|line| {
+      let mut fields = line.split('\t');
+      let rel_path = fields.next()?;
+      // Sub-second mtimes are the norm (ext4, tmpfs, ...): truncating to whole seconds here
+      // would make an unmodified file look "changed" on every single acquire, so we keep full
+      // nanosecond precision round-tripping through this file.
+      let seconds: u64 = fields.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+      let nanos: u32 = fields.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+      Some((
+        RelativePath::from(rel_path),
+        UNIX_EPOCH + std::time::Duration::new(seconds, nanos),
+      ))
+    })
+    .collect()
+}
+
+fn store_fingerprint_map(workdir: &Path, map: &FingerprintMap) -> io::Result<()> {
+  let mut contents = String::new();
+  for (rel_path, mtime) in map {
+    let duration = mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+    contents.push_str(&format!(
+      "{}\t{}\t{}\n",
+      rel_path.display(),
+      duration.as_secs(),
+      duration.subsec_nanos()
+    ));
+  }
+  let path = workdir.join(FINGERPRINT_MAP_FILENAME);
+  fs::write(&path, contents)?;
+  fs::File::open(&path)?.sync_all()
+}
+
+/// Returns the first of `inputs` that is missing, unreadable, or newer than `anchor`, if any.
+/// A missing or unreadable input is treated as stale (fail-safe): we'd rather pay for an
+/// unnecessary restart than silently hand back a server pointed at inputs that vanished.
+/// Mirrors the shape of Cargo's own fingerprint freshness check (`find_stale_file` /
+/// `check_filesystem`).
+fn find_stale_file(anchor: SystemTime, inputs: &[PathBuf]) -> Option<PathBuf> {
+  for input in inputs {
+    match fs::metadata(input).and_then(|m| m.modified()) {
+      Ok(mtime) if mtime <= anchor => continue,
+      _ => return Some(input.clone()),
+    }
+  }
+  None
+}
+
+fn parse_port(line: &str) -> Option<u16> {
+  line
+    .split_whitespace()
+    .rev()
+    .find_map(|token| token.trim_matches(|c: char| !c.is_ascii_digit()).parse().ok())
+}
+
+/// A handle to a running nailgun server, acquired from a `NailgunPool`.
+pub struct NailgunProcessHandle {
+  pool: Arc<Mutex<Vec<RunningProcess>>>,
+  store: Store,
+  executor: Executor,
+  workdir: PathBuf,
+  port: u16,
+  capture_outputs: bool,
+}
+
+impl NailgunProcessHandle {
+  pub fn port(&self) -> u16 {
+    self.port
+  }
+
+  pub fn workdir_path(&self) -> &Path {
+    &self.workdir
+  }
+
+  /// Returns this server to the pool, allowing a subsequent `acquire` with a matching
+  /// fingerprint to reuse it. If the `Process` opted into `capture_outputs`, the workdir's
+  /// contents are first ingested into the `Store` as a content-addressed directory tree, whose
+  /// root digest is returned.
+  pub async fn release(&mut self) -> Result<Option<Digest>, String> {
+    let digest = if self.capture_outputs {
+      Some(
+        capture_directory(
+          &self.executor,
+          &self.store,
+          self.workdir.clone(),
+          &[FINGERPRINT_MAP_FILENAME, CHANGED_PATHS_FILENAME],
+        )
+        .await?,
+      )
+    } else {
+      None
+    };
+
+    let mut processes = self.pool.lock();
+    if let Some(running) = processes.iter_mut().find(|p| p.workdir == self.workdir) {
+      running.in_use = false;
+    }
+    Ok(digest)
+  }
+}
+
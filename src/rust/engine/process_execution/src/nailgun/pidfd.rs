@@ -0,0 +1,50 @@
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use tokio::process::Child;
+
+/// A `pidfd` (`pidfd_open(2)`) for a spawned child: a file descriptor that refers to the
+/// process itself rather than its pid, so liveness checks made *after* this is opened aren't
+/// subject to the pid being reaped and reused by an unrelated process between when we observe
+/// it and when we act on it.
+///
+/// This is opened via `pidfd_open(2)` right after `spawn()` returns, not atomically at
+/// clone/fork time (`CLONE_PIDFD`, which `std::process::Command` has no stable API for).
+/// That leaves a narrow window, between the kernel allocating the pid and us calling
+/// `pidfd_open`, during which the pid isn't yet protected by this fd; in practice the pid
+/// can't have been reaped and recycled in that handful of instructions, since the process we
+/// just spawned is still running and we hold the only `Child` handle to it. What this does
+/// eliminate is the much larger, real-world race: every liveness check from here on uses the
+/// pidfd rather than re-deriving a pid from disk or from an earlier snapshot, so none of them
+/// can be fooled by pid reuse.
+pub struct PidFd(OwnedFd);
+
+/// Opens a pidfd for `child`'s pid. Returns an error if the child has already been reaped (it
+/// has no pid) or the kernel doesn't support `pidfd_open` (pre-5.3).
+pub fn open(child: &Child) -> io::Result<PidFd> {
+  let pid = child
+    .id()
+    .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "child has already exited"))?;
+  let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+  if fd < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(PidFd(unsafe { OwnedFd::from_raw_fd(fd as RawFd) }))
+}
+
+impl PidFd {
+  /// Polls the pidfd for `POLLIN`, which the kernel posts exactly when the process has exited:
+  /// still running iff the poll reports nothing ready.
+  pub fn is_alive(&self) -> io::Result<bool> {
+    let mut pfd = libc::pollfd {
+      fd: self.0.as_raw_fd(),
+      events: libc::POLLIN,
+      revents: 0,
+    };
+    let rc = unsafe { libc::poll(&mut pfd, 1, 0) };
+    if rc < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(rc == 0)
+  }
+}
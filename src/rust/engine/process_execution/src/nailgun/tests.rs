@@ -1,18 +1,30 @@
+use std::fs;
+use std::os::unix::fs::{symlink, PermissionsExt};
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use store::Store;
 use task_executor::Executor;
 use tempfile::TempDir;
 use testutil::owned_string_vec;
 
-use crate::nailgun::NailgunPool;
+use crate::nailgun::{load_directory, NailgunPool};
 use crate::Process;
 
 fn pool(size: usize) -> NailgunPool {
+  pool_with_store(size).0
+}
+
+/// Like `pool`, but also returns the `Store` backing it, for tests that need to load captured
+/// output digests back out to inspect their contents.
+fn pool_with_store(size: usize) -> (NailgunPool, Store) {
   let store_dir = TempDir::new().unwrap();
   let executor = Executor::new();
   let store = Store::local_only(executor.clone(), store_dir.path()).unwrap();
-  NailgunPool::new(std::env::temp_dir(), size, store, executor)
+  (
+    NailgunPool::new(std::env::temp_dir(), size, store.clone(), executor),
+    store,
+  )
 }
 
 async fn run(pool: &NailgunPool, port: u16) -> PathBuf {
@@ -43,3 +55,312 @@ async fn acquire() {
   let workdir_three = run(&pool, 200).await;
   assert_ne!(workdir_two, workdir_three);
 }
+
+#[tokio::test]
+async fn acquire_picks_up_changed_input() {
+  let pool = pool(1);
+  let inputs_dir = TempDir::new().unwrap();
+  let src = inputs_dir.path().join("a.txt");
+  fs::write(&src, "one").unwrap();
+
+  let mut process = Process::new(owned_string_vec(&[
+    "/bin/bash",
+    "-c",
+    "echo Mock port 300.; sleep 10",
+  ]));
+  process.input_files.insert(PathBuf::from("a.txt"), src.clone());
+
+  let mut p = pool.acquire(process.clone()).await.unwrap();
+  let workdir = p.workdir_path().to_owned();
+  assert_eq!("one", fs::read_to_string(workdir.join("a.txt")).unwrap());
+  p.release().await.unwrap();
+
+  // Changing the input's content (and mtime) and reacquiring with the same fingerprint should
+  // pick up the change in the same workdir path. Since the input is also one the server was
+  // started with, its newer mtime restarts the server rather than incrementally syncing it.
+  fs::write(&src, "two").unwrap();
+  let mut p = pool.acquire(process).await.unwrap();
+  assert_eq!("two", fs::read_to_string(workdir.join("a.txt")).unwrap());
+  p.release().await.unwrap();
+}
+
+#[tokio::test]
+async fn acquire_skips_recopy_of_unchanged_inputs() {
+  let pool = pool(1);
+  let inputs_dir = TempDir::new().unwrap();
+  let src = inputs_dir.path().join("a.txt");
+  fs::write(&src, "one").unwrap();
+
+  let mut process = Process::new(owned_string_vec(&[
+    "/bin/bash",
+    "-c",
+    "echo Mock port 950.; sleep 10",
+  ]));
+  process.input_files.insert(PathBuf::from("a.txt"), src.clone());
+
+  let mut p = pool.acquire(process.clone()).await.unwrap();
+  let workdir = p.workdir_path().to_owned();
+  p.release().await.unwrap();
+
+  // Remove the materialized copy without touching the source. If the fingerprint map
+  // correctly recognizes the (untouched) input as already synced, the next acquire must not
+  // recopy it, and the deletion above stays in effect.
+  fs::remove_file(workdir.join("a.txt")).unwrap();
+
+  let mut p = pool.acquire(process).await.unwrap();
+  assert!(!workdir.join("a.txt").exists());
+  p.release().await.unwrap();
+}
+
+#[tokio::test]
+async fn acquire_handles_input_flipping_from_directory_to_file() {
+  let pool = pool(1);
+  let inputs_dir = TempDir::new().unwrap();
+  let nested_src = inputs_dir.path().join("nested.txt");
+  fs::write(&nested_src, "nested").unwrap();
+
+  let argv = owned_string_vec(&["/bin/bash", "-c", "echo Mock port 960.; sleep 10"]);
+
+  let mut process = Process::new(argv.clone());
+  process
+    .input_files
+    .insert(PathBuf::from("foo/a.txt"), nested_src.clone());
+
+  let mut p = pool.acquire(process).await.unwrap();
+  let workdir = p.workdir_path().to_owned();
+  assert!(workdir.join("foo").is_dir());
+  p.release().await.unwrap();
+
+  // The same fingerprint (same argv), but the input set now places a plain file at "foo"
+  // instead of a directory containing "foo/a.txt".
+  let flat_src = inputs_dir.path().join("flat.txt");
+  fs::write(&flat_src, "flat").unwrap();
+  let mut process = Process::new(argv);
+  process.input_files.insert(PathBuf::from("foo"), flat_src.clone());
+
+  let mut p = pool.acquire(process).await.unwrap();
+  assert_eq!("flat", fs::read_to_string(workdir.join("foo")).unwrap());
+  p.release().await.unwrap();
+}
+
+#[tokio::test]
+async fn acquire_restarts_server_with_stale_classpath() {
+  let pool = pool(1);
+  let inputs_dir = TempDir::new().unwrap();
+  let classpath_jar = inputs_dir.path().join("lib.jar");
+  fs::write(&classpath_jar, "v1").unwrap();
+
+  let mut process = Process::new(owned_string_vec(&[
+    "/bin/bash",
+    "-c",
+    "echo Mock port 400.; sleep 10",
+  ]));
+  process
+    .input_files
+    .insert(PathBuf::from("lib.jar"), classpath_jar.clone());
+
+  let mut p = pool.acquire(process.clone()).await.unwrap();
+  let workdir = p.workdir_path().to_owned();
+  // Plant a marker that only a full restart (which wipes the workdir) would remove; a plain
+  // incremental sync would leave it untouched.
+  fs::write(workdir.join("marker"), "present").unwrap();
+  p.release().await.unwrap();
+
+  // Rebuilding the classpath jar underneath the still-running server must not be silently
+  // ignored, even though the fingerprint hasn't changed.
+  fs::write(&classpath_jar, "v2").unwrap();
+  let mut p = pool.acquire(process).await.unwrap();
+  assert!(!workdir.join("marker").exists());
+  assert_eq!("v2", fs::read_to_string(workdir.join("lib.jar")).unwrap());
+  p.release().await.unwrap();
+}
+
+#[tokio::test]
+async fn release_captures_outputs_when_requested() {
+  let (pool, store) = pool_with_store(1);
+
+  let mut process = Process::new(owned_string_vec(&[
+    "/bin/bash",
+    "-c",
+    "echo Mock port 500.; sleep 10",
+  ]));
+  process.capture_outputs = true;
+
+  let mut p = pool.acquire(process).await.unwrap();
+  let workdir = p.workdir_path().to_owned();
+  fs::write(workdir.join("output.txt"), "built").unwrap();
+  fs::write(workdir.join("run.sh"), "#!/bin/sh\necho hi\n").unwrap();
+  fs::set_permissions(workdir.join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+  fs::create_dir(workdir.join("nested")).unwrap();
+  fs::write(workdir.join("nested/inner.txt"), "inner").unwrap();
+  symlink("output.txt", workdir.join("link.txt")).unwrap();
+
+  let digest = p.release().await.unwrap().expect("capture_outputs requested a digest");
+  let directory = load_directory(&store, digest).await.unwrap();
+
+  // The nailgun bookkeeping files must not leak into the captured tree.
+  assert!(directory.files.iter().all(|f| f.name != super::FINGERPRINT_MAP_FILENAME));
+  assert!(directory.files.iter().all(|f| f.name != super::CHANGED_PATHS_FILENAME));
+
+  let output = directory.files.iter().find(|f| f.name == "output.txt").unwrap();
+  assert!(!output.is_executable);
+  let output_bytes = store.load_file_bytes(output.digest).await.unwrap().unwrap();
+  assert_eq!(b"built".as_slice(), output_bytes.as_ref());
+
+  let run_sh = directory.files.iter().find(|f| f.name == "run.sh").unwrap();
+  assert!(run_sh.is_executable);
+
+  let link = directory.symlinks.iter().find(|s| s.name == "link.txt").unwrap();
+  assert_eq!("output.txt", link.target);
+
+  let (_, nested_digest) = directory.directories.iter().find(|(name, _)| name == "nested").unwrap();
+  let nested = load_directory(&store, *nested_digest).await.unwrap();
+  let inner = nested.files.iter().find(|f| f.name == "inner.txt").unwrap();
+  let inner_bytes = store.load_file_bytes(inner.digest).await.unwrap().unwrap();
+  assert_eq!(b"inner".as_slice(), inner_bytes.as_ref());
+}
+
+#[tokio::test]
+async fn release_captures_names_containing_spaces_and_tabs() {
+  let (pool, store) = pool_with_store(1);
+
+  let mut process = Process::new(owned_string_vec(&[
+    "/bin/bash",
+    "-c",
+    "echo Mock port 510.; sleep 10",
+  ]));
+  process.capture_outputs = true;
+
+  let mut p = pool.acquire(process).await.unwrap();
+  let workdir = p.workdir_path().to_owned();
+  // A space, and a literal tab, are both legal in a POSIX filename and in a symlink target;
+  // the capture format's own field delimiter is a tab, so a tab must round-trip escaped while
+  // a space passes through unchanged.
+  fs::write(workdir.join("an output file.txt"), "spacey").unwrap();
+  symlink("an output file.txt", workdir.join("a\tlink.txt")).unwrap();
+
+  let digest = p.release().await.unwrap().expect("capture_outputs requested a digest");
+  let directory = load_directory(&store, digest).await.unwrap();
+
+  let output = directory.files.iter().find(|f| f.name == "an output file.txt").unwrap();
+  let output_bytes = store.load_file_bytes(output.digest).await.unwrap().unwrap();
+  assert_eq!(b"spacey".as_slice(), output_bytes.as_ref());
+
+  let link = directory.symlinks.iter().find(|s| s.name == "a\tlink.txt").unwrap();
+  assert_eq!("an output file.txt", link.target);
+}
+
+#[tokio::test]
+async fn release_does_not_capture_outputs_by_default() {
+  let pool = pool(1);
+  let mut p = pool
+    .acquire(Process::new(owned_string_vec(&[
+      "/bin/bash",
+      "-c",
+      "echo Mock port 600.; sleep 10",
+    ])))
+    .await
+    .unwrap();
+  assert!(p.release().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn spawn_overrides_argv0() {
+  let pool = pool(1);
+  let mut process = Process::new(owned_string_vec(&[
+    "/bin/bash",
+    "-c",
+    "echo Mock port 800.; echo \"$0\" > argv0.txt; sleep 10",
+  ]));
+  process.argv0 = Some("nailgun-server".to_owned());
+
+  let mut p = pool.acquire(process).await.unwrap();
+  let workdir = p.workdir_path().to_owned();
+  // Give the server a moment to run the echo above before we release it.
+  tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+  assert_eq!(
+    "nailgun-server",
+    fs::read_to_string(workdir.join("argv0.txt")).unwrap().trim()
+  );
+  p.release().await.unwrap();
+}
+
+#[tokio::test]
+async fn spawn_runs_pre_exec_actions_in_the_child() {
+  let pool = pool(1);
+  let marker_dir = TempDir::new().unwrap();
+  let marker = marker_dir.path().join("pre_exec_ran");
+  let marker_in_child = marker.clone();
+
+  let mut process = Process::new(owned_string_vec(&[
+    "/bin/bash",
+    "-c",
+    "echo Mock port 810.; sleep 10",
+  ]));
+  process.pre_exec.push(Arc::new(move || {
+    fs::write(&marker_in_child, "")?;
+    Ok(())
+  }));
+
+  let mut p = pool.acquire(process).await.unwrap();
+  // The closure runs in the forked child immediately before exec (not in this process), so a
+  // file it writes (rather than an in-memory flag, which would only mutate the child's own
+  // copy-on-write memory) is the only way to observe that it actually ran.
+  assert!(marker.exists());
+  p.release().await.unwrap();
+}
+
+#[tokio::test]
+async fn spawn_sets_uid_and_gid() {
+  let pool = pool(1);
+  // There's no way to safely drop to an unprivileged uid/gid in a test that may itself be
+  // running unprivileged, so this only exercises the wiring with the current process' own
+  // ids, which every process is always permitted to "switch" to.
+  let mut process = Process::new(owned_string_vec(&[
+    "/bin/bash",
+    "-c",
+    "echo Mock port 820.; sleep 10",
+  ]));
+  process.uid = Some(unsafe { libc::getuid() });
+  process.gid = Some(unsafe { libc::getgid() });
+
+  let mut p = pool.acquire(process).await.unwrap();
+  assert_eq!(820, p.port());
+  p.release().await.unwrap();
+}
+
+#[tokio::test]
+async fn acquire_does_not_reuse_server_spawned_under_a_different_uid() {
+  let pool = pool(2);
+  let argv = owned_string_vec(&["/bin/bash", "-c", "echo Mock port 830.; sleep 10"]);
+
+  let mut plain = Process::new(argv.clone());
+  let mut p = pool.acquire(plain.clone()).await.unwrap();
+  let plain_workdir = p.workdir_path().to_owned();
+  p.release().await.unwrap();
+
+  // Same argv, but now asking to run under an explicit uid: this must not be served by the
+  // server spawned above, since that one was never actually started under this identity.
+  plain.uid = Some(unsafe { libc::getuid() });
+  let mut p = pool.acquire(plain).await.unwrap();
+  assert_ne!(plain_workdir, p.workdir_path());
+  p.release().await.unwrap();
+}
+
+#[tokio::test]
+async fn acquire_restarts_dead_server() {
+  let pool = pool(1);
+  let process = Process::new(owned_string_vec(&["/bin/bash", "-c", "echo Mock port 700.; exit 0"]));
+
+  let mut p = pool.acquire(process.clone()).await.unwrap();
+  p.release().await.unwrap();
+
+  // Give the short-lived mock server time to actually exit.
+  tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+  // Re-acquiring with the same fingerprint must notice the server is gone and restart it,
+  // rather than handing back a dead entry.
+  let mut p = pool.acquire(process).await.unwrap();
+  assert_eq!(700, p.port());
+  p.release().await.unwrap();
+}